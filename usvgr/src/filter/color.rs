@@ -0,0 +1,43 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/// A color space for color interpolation.
+///
+/// `color-interpolation-filters` in the SVG. Every filter primitive kind can
+/// resolve and carry this value for its own compositing needs.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, PartialEq, Hash, Debug)]
+pub enum ColorInterpolation {
+    SRGB,
+    LinearRGB,
+}
+
+impl Default for ColorInterpolation {
+    fn default() -> Self {
+        // linearRGB is the initial value per the Filter Effects spec.
+        ColorInterpolation::LinearRGB
+    }
+}
+
+/// Converts an 8-bit sRGB channel value into linearRGB, in the `0..=1` range.
+pub fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linearRGB channel value in the `0..=1` range into 8-bit sRGB.
+pub fn linear_to_srgb(c: f64) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+
+    (c * 255.0 + 0.5) as u8
+}